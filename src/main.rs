@@ -1,10 +1,17 @@
+use std::collections::HashMap;
 use std::env;
 use std::process;
 use std::result::Result;
+use std::time::{Duration, Instant};
 
+use dashmap::DashMap;
+use futures::stream::{Stream, StreamExt};
+use rand::Rng;
 use serenity::async_trait;
+use serenity::builder::{CreateAttachment, CreateMessage, EditMessage};
 use serenity::model::channel::Message;
 use serenity::model::gateway::Ready;
+use serenity::model::id::ChannelId;
 use serenity::prelude::*;
 
 use reqwest::header::{HeaderMap, CONTENT_TYPE};
@@ -12,67 +19,583 @@ use reqwest::Error;
 use serde::{Deserialize, Serialize};
 
 const ENDPOINT: &'static str = "https://api.openai.com/v1/chat/completions";
+const DEFAULT_MODEL: &'static str = "gpt-3.5-turbo";
+const DEFAULT_PROVIDER: &'static str = "default";
 const ERROR_MESSAGE: &'static str = "Unable to get a response. If this problem continues, please contact the administrator of the bot.";
+const RESET_COMMAND: &'static str = "!reset";
+const PERSONA_COMMAND: &'static str = "!persona";
+const DEFAULT_PERSONA: &'static str = "default";
+// Rough cap on how many messages of history we replay per request. Counting
+// messages rather than tokens keeps the trim logic cheap; this is generous
+// enough to stay under the context window for gpt-3.5-turbo. Overridable via
+// the `HISTORY_MAX_MESSAGES` env var.
+const DEFAULT_MAX_HISTORY_MESSAGES: usize = 20;
+// How often the placeholder Discord reply is allowed to be edited while a
+// stream is in flight, to stay well clear of Discord's per-message rate limit.
+const STREAM_EDIT_INTERVAL: Duration = Duration::from_secs(1);
+const STREAM_PLACEHOLDER: &'static str = "...";
+const RATE_LIMITED_MESSAGE: &'static str =
+    "The model is rate limiting us right now. Please try again in a moment.";
+// Retry tuning for transient 429 / 5xx responses: exponential backoff from
+// BASE_RETRY_DELAY, doubling per attempt, capped at MAX_RETRY_DELAY, plus a
+// little jitter so a burst of requests doesn't retry in lockstep.
+const MAX_RETRY_ATTEMPTS: u32 = 5;
+const BASE_RETRY_DELAY: Duration = Duration::from_millis(500);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+const IMAGE_ENDPOINT: &'static str = "https://api.openai.com/v1/images/generations";
+const IMAGE_COMMAND: &'static str = "!image";
+const DEFAULT_IMAGE_SIZE: &'static str = "1024x1024";
+const MAX_IMAGES_PER_REQUEST: u32 = 4;
+const ASK_COMMAND: &'static str = "!ask";
+const MAX_GENERATION_N: u32 = 4;
+// Discord rejects messages over 2000 characters, so multi-choice (or just
+// long) replies are split across several messages instead of one.
+const DISCORD_MESSAGE_LIMIT: usize = 2000;
 
-#[derive(Debug, Deserialize, Serialize)]
-struct ChatCompletion {
-    id: String,
-    object: String,
-    created: i64,
-    model: String,
-    usage: Usage,
-    choices: Vec<Choice>,
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct GPTMessage {
+    role: String,
+    content: String,
+}
+
+/// One `data: {...}` line of an OpenAI SSE chat-completions stream.
+#[derive(Debug, Deserialize)]
+struct StreamChunk {
+    choices: Vec<StreamChoice>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamChoice {
+    delta: StreamDelta,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamDelta {
+    content: Option<String>,
+}
+
+/// Distinguishes "we gave up after repeated 429 / 5xx responses" and "the
+/// backend rejected the request outright" from a transport-level failure, so the
+/// handler can show the user a more specific message than the generic
+/// [`ERROR_MESSAGE`].
+#[derive(Debug)]
+enum AskGptError {
+    RateLimited,
+    Http(reqwest::StatusCode),
+    Request(Error),
+}
+
+impl std::fmt::Display for AskGptError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AskGptError::RateLimited => {
+                write!(f, "gave up after repeated rate limit / server errors")
+            }
+            AskGptError::Http(status) => write!(f, "request rejected with status {status}"),
+            AskGptError::Request(err) => write!(f, "request failed: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for AskGptError {}
+
+impl From<Error> for AskGptError {
+    fn from(err: Error) -> Self {
+        AskGptError::Request(err)
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct Usage {
-    prompt_tokens: i32,
-    completion_tokens: i32,
-    total_tokens: i32,
+#[derive(Debug, Serialize)]
+struct ImageGenerationRequest {
+    prompt: String,
+    n: u32,
+    size: String,
+    response_format: String,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize)]
+struct ImageGenerationResponse {
+    data: Vec<ImageData>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ImageData {
+    url: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatCompletion {
+    choices: Vec<Choice>,
+}
+
+#[derive(Debug, Deserialize)]
 struct Choice {
     message: GPTMessage,
-    finish_reason: String,
-    index: i32,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-struct GPTMessage {
-    role: String,
-    content: String,
+/// Per-request generation parameters for the chat-completions API. Fields
+/// left as `None` are omitted from the request body, so the backend's own
+/// defaults apply.
+#[derive(Clone, Debug, Default)]
+struct GenerationConfig {
+    model: Option<String>,
+    temperature: Option<f32>,
+    max_tokens: Option<u32>,
+    top_p: Option<f32>,
+    n: Option<u32>,
+}
+
+impl GenerationConfig {
+    /// Loads server-wide defaults from `DEFAULT_TEMPERATURE` /
+    /// `DEFAULT_MAX_TOKENS` / `DEFAULT_TOP_P` / `DEFAULT_N`, all optional.
+    fn from_env() -> Self {
+        GenerationConfig {
+            model: None,
+            temperature: env::var("DEFAULT_TEMPERATURE").ok().and_then(|v| v.parse().ok()),
+            max_tokens: env::var("DEFAULT_MAX_TOKENS").ok().and_then(|v| v.parse().ok()),
+            top_p: env::var("DEFAULT_TOP_P").ok().and_then(|v| v.parse().ok()),
+            n: env::var("DEFAULT_N").ok().and_then(|v| v.parse().ok()),
+        }
+    }
+}
+
+/// A named OpenAI-compatible backend. Several servers (OpenAI, a self-hosted
+/// llama.cpp server, Azure OpenAI deployments, ...) all speak the same
+/// `/chat/completions` JSON schema, so routing between them only requires
+/// swapping these three values.
+#[derive(Clone, Debug)]
+struct Provider {
+    base_url: String,
+    api_key: String,
+    model: String,
+}
+
+/// Reads a named provider's config from `{ALIAS}_API_BASE` / `_API_KEY` /
+/// `_MODEL` env vars. Returns `None` if the base URL isn't set, so operators
+/// only pay for the providers they actually configure.
+fn provider_from_env(alias: &str, fallback_api_key: &str) -> Option<Provider> {
+    let prefix = alias.to_uppercase();
+    let base_url = env::var(format!("{}_API_BASE", prefix)).ok()?;
+    let model = env::var(format!("{}_MODEL", prefix)).unwrap_or_else(|_| DEFAULT_MODEL.to_string());
+    let api_key =
+        env::var(format!("{}_API_KEY", prefix)).unwrap_or_else(|_| fallback_api_key.to_string());
+
+    Some(Provider {
+        base_url,
+        api_key,
+        model,
+    })
+}
+
+/// Strips a leading command token (e.g. `!persona`) from `content`, but only
+/// when it's followed by a space or the end of the string — so `!personally`
+/// or `!persona-foo` aren't mistaken for the `!persona` command. Returns the
+/// trimmed remainder on a match.
+fn strip_command<'a>(content: &'a str, command: &str) -> Option<&'a str> {
+    let rest = content.strip_prefix(command)?;
+    if rest.is_empty() || rest.starts_with(' ') {
+        Some(rest.trim_start())
+    } else {
+        None
+    }
+}
+
+/// Builds the table of named system prompts a channel can switch between
+/// with `!persona <name>`. `DEFAULT_PERSONA` comes from `SYSTEM_PROMPT` and,
+/// when set, is prepended to every request that hasn't picked another one.
+/// Additional personas are opt-in via `PERSONA_<NAME>` env vars, for the
+/// fixed set of names listed below only (`-` becomes `_` in the var name).
+fn load_personas() -> HashMap<String, String> {
+    let mut personas = HashMap::new();
+    if let Ok(prompt) = env::var("SYSTEM_PROMPT") {
+        personas.insert(DEFAULT_PERSONA.to_string(), prompt);
+    }
+    for alias in ["helpful-rust-tutor", "concise", "creative"] {
+        let env_key = format!("PERSONA_{}", alias.to_uppercase().replace('-', "_"));
+        if let Ok(prompt) = env::var(env_key) {
+            personas.insert(alias.to_string(), prompt);
+        }
+    }
+    personas
 }
 
 struct Bot {
-    openai_token: String,
     http_client: reqwest::Client,
+    history: DashMap<ChannelId, Vec<GPTMessage>>,
+    providers: HashMap<String, Provider>,
+    personas: HashMap<String, String>,
+    channel_persona: DashMap<ChannelId, String>,
+    generation_defaults: GenerationConfig,
+    max_history_messages: usize,
+}
+
+/// Builds the `Content-Type: application/json` + `Authorization: Bearer …`
+/// headers shared by every OpenAI-compatible request.
+fn build_headers(api_key: &str) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_TYPE, "application/json".parse().unwrap());
+    headers.insert(
+        "Authorization",
+        format!("Bearer {}", api_key).parse().unwrap(),
+    );
+    headers
+}
+
+/// Whether a response status is worth retrying: `429 Too Many Requests` or
+/// any `5xx`. Everything else (4xx client errors like a bad API key or a
+/// malformed request) is a hard failure and gives up immediately.
+fn is_retryable_status(status: reqwest::StatusCode) -> bool {
+    status.as_u16() == 429 || status.is_server_error()
+}
+
+/// Exponential backoff with jitter for the `attempt`-th retry (0-indexed).
+fn backoff_delay(attempt: u32) -> Duration {
+    let exp = BASE_RETRY_DELAY.saturating_mul(1 << attempt.min(6));
+    let capped = exp.min(MAX_RETRY_DELAY);
+    let jitter = Duration::from_millis(rand::thread_rng().gen_range(0..250));
+    capped + jitter
+}
+
+/// Pulls `--n <count>` and `--size <WxH>` flags out of a `!image` command's
+/// arguments, returning the remaining words as the prompt.
+fn parse_image_args(input: &str) -> (String, u32, String) {
+    let mut n = 1u32;
+    let mut size = DEFAULT_IMAGE_SIZE.to_string();
+    let mut prompt_words = Vec::new();
+
+    let mut tokens = input.split_whitespace();
+    while let Some(token) = tokens.next() {
+        match token {
+            "--n" => {
+                if let Some(value) = tokens.next().and_then(|v| v.parse().ok()) {
+                    n = value;
+                }
+            }
+            "--size" => {
+                if let Some(value) = tokens.next() {
+                    size = value.to_string();
+                }
+            }
+            other => prompt_words.push(other),
+        }
+    }
+
+    (prompt_words.join(" "), n, size)
+}
+
+/// Overrides `defaults` with any `--temp` / `--max` / `--top_p` / `--n` /
+/// `--model` flags found in a `!ask` command's arguments, returning the
+/// resolved config and the remaining words as the prompt.
+fn parse_generation_args(input: &str, defaults: &GenerationConfig) -> (GenerationConfig, String) {
+    let mut config = defaults.clone();
+    let mut prompt_words = Vec::new();
+
+    let mut tokens = input.split_whitespace();
+    while let Some(token) = tokens.next() {
+        match token {
+            "--temp" => {
+                if let Some(value) = tokens.next().and_then(|v| v.parse().ok()) {
+                    config.temperature = Some(value);
+                }
+            }
+            "--max" => {
+                if let Some(value) = tokens.next().and_then(|v| v.parse().ok()) {
+                    config.max_tokens = Some(value);
+                }
+            }
+            "--top_p" => {
+                if let Some(value) = tokens.next().and_then(|v| v.parse().ok()) {
+                    config.top_p = Some(value);
+                }
+            }
+            "--n" => {
+                if let Some(value) = tokens.next().and_then(|v| v.parse().ok()) {
+                    config.n = Some(value);
+                }
+            }
+            "--model" => {
+                if let Some(value) = tokens.next() {
+                    config.model = Some(value.to_string());
+                }
+            }
+            other => prompt_words.push(other),
+        }
+    }
+
+    (config, prompt_words.join(" "))
+}
+
+/// Splits `text` into pieces no longer than `limit` characters, so it fits
+/// within Discord's per-message length cap across one or more replies.
+fn split_into_chunks(text: &str, limit: usize) -> Vec<String> {
+    if text.is_empty() {
+        return vec![String::new()];
+    }
+    text.chars()
+        .collect::<Vec<_>>()
+        .chunks(limit)
+        .map(|chunk| chunk.iter().collect())
+        .collect()
 }
 
 impl Bot {
-    async fn ask_gpt(&self, message: GPTMessage) -> Result<GPTMessage, Error> {
-        let mut headers = HeaderMap::new();
-        headers.insert(CONTENT_TYPE, "application/json".parse().unwrap());
-        headers.insert(
-            "Authorization",
-            format!("Bearer {}", self.openai_token).parse().unwrap(),
-        );
+    /// Picks the provider and trimmed message content a Discord message
+    /// addresses, based on a leading `!<alias>` command prefix (e.g.
+    /// `!mistral what's up`). Falls back to [`DEFAULT_PROVIDER`] when no
+    /// known alias is found, so plain messages keep working unchanged.
+    fn resolve_provider<'a>(&'a self, content: &str) -> (&'a Provider, String) {
+        if let Some(rest) = content.strip_prefix('!') {
+            if let Some((alias, tail)) = rest.split_once(' ') {
+                if let Some(provider) = self.providers.get(alias) {
+                    return (provider, tail.trim_start().to_string());
+                }
+            }
+        }
 
-        let body = serde_json::json!({
-            "model": "gpt-3.5-turbo",
-            "messages": vec![message]
+        let provider = self
+            .providers
+            .get(DEFAULT_PROVIDER)
+            .expect("default provider is always configured");
+        (provider, content.to_string())
+    }
+
+    /// Sends a request built by `build_request`, retrying on `429` and `5xx`
+    /// responses with exponential backoff (honoring `Retry-After` when the
+    /// server sends one) up to [`MAX_RETRY_ATTEMPTS`] times before giving up.
+    async fn send_with_retry<F>(&self, build_request: F) -> Result<reqwest::Response, AskGptError>
+    where
+        F: Fn() -> reqwest::RequestBuilder,
+    {
+        let mut attempt = 0;
+        loop {
+            let response = build_request().send().await?;
+            let status = response.status();
+            if status.is_success() {
+                return Ok(response);
+            }
+
+            if !is_retryable_status(status) {
+                return Err(AskGptError::Http(status));
+            }
+            if attempt >= MAX_RETRY_ATTEMPTS {
+                return Err(AskGptError::RateLimited);
+            }
+
+            let delay = response
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse::<u64>().ok())
+                .map(Duration::from_secs)
+                .unwrap_or_else(|| backoff_delay(attempt));
+
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /// Requests `n` generated images of the given `size` for `prompt` from
+    /// the DALL·E images endpoint, reusing the default provider's API key
+    /// and the same retry policy as chat completions.
+    async fn ask_dalle(&self, prompt: &str, n: u32, size: &str) -> Result<Vec<String>, AskGptError> {
+        let provider = self
+            .providers
+            .get(DEFAULT_PROVIDER)
+            .expect("default provider is always configured");
+
+        let headers = build_headers(&provider.api_key);
+
+        let body = ImageGenerationRequest {
+            prompt: prompt.to_string(),
+            n,
+            size: size.to_string(),
+            response_format: "url".to_string(),
+        };
+
+        let endpoint = env::var("OPENAI_IMAGE_BASE").unwrap_or_else(|_| IMAGE_ENDPOINT.to_string());
+        let response = self
+            .send_with_retry(|| {
+                self.http_client
+                    .post(&endpoint)
+                    .headers(headers.clone())
+                    .json(&body)
+            })
+            .await?;
+
+        let output: ImageGenerationResponse = response.json().await?;
+        Ok(output.data.into_iter().map(|image| image.url).collect())
+    }
+
+    /// Runs a one-shot (non-streaming) chat completion with explicit
+    /// generation parameters, returning every requested choice. Used by
+    /// `!ask`, where `config.n > 1` asks for several alternatives at once —
+    /// something that doesn't fit the live-edited streaming path.
+    async fn ask_gpt_with_config(
+        &self,
+        provider: &Provider,
+        channel_id: ChannelId,
+        message: &GPTMessage,
+        config: &GenerationConfig,
+    ) -> Result<Vec<GPTMessage>, AskGptError> {
+        let mut messages = Vec::new();
+        messages.extend(self.system_message_for(channel_id));
+        messages.extend(self.history.get(&channel_id).map_or(Vec::new(), |h| h.clone()));
+        messages.push(message.clone());
+
+        let headers = build_headers(&provider.api_key);
+
+        let mut body = serde_json::json!({
+            "model": config.model.clone().unwrap_or_else(|| provider.model.clone()),
+            "messages": messages,
         });
+        if let Some(temperature) = config.temperature {
+            body["temperature"] = serde_json::json!(temperature);
+        }
+        if let Some(max_tokens) = config.max_tokens {
+            body["max_tokens"] = serde_json::json!(max_tokens);
+        }
+        if let Some(top_p) = config.top_p {
+            body["top_p"] = serde_json::json!(top_p);
+        }
+        if let Some(n) = config.n {
+            body["n"] = serde_json::json!(n);
+        }
 
         let response = self
-            .http_client
-            .post(ENDPOINT)
-            .headers(headers)
-            .json(&body)
-            .send()
+            .send_with_retry(|| {
+                self.http_client
+                    .post(&provider.base_url)
+                    .headers(headers.clone())
+                    .json(&body)
+            })
             .await?;
 
         let output: ChatCompletion = response.json().await?;
-        Ok(output.choices[0].message.clone())
+        Ok(output.choices.into_iter().map(|choice| choice.message).collect())
+    }
+
+    /// Opens a streaming chat-completion request and returns a stream of
+    /// text deltas as they arrive over SSE. Does not touch `self.history` —
+    /// callers accumulate the full reply themselves and persist it via
+    /// [`Bot::commit_history`] once the stream ends, since the reply text
+    /// isn't known until then.
+    async fn ask_gpt_stream(
+        &self,
+        provider: &Provider,
+        channel_id: ChannelId,
+        message: &GPTMessage,
+    ) -> Result<impl Stream<Item = String>, AskGptError> {
+        let mut messages = Vec::new();
+        messages.extend(self.system_message_for(channel_id));
+        messages.extend(self.history.get(&channel_id).map_or(Vec::new(), |h| h.clone()));
+        messages.push(message.clone());
+
+        let headers = build_headers(&provider.api_key);
+
+        let body = serde_json::json!({
+            "model": provider.model,
+            "messages": messages,
+            "stream": true,
+        });
+
+        let response = self
+            .send_with_retry(|| {
+                self.http_client
+                    .post(&provider.base_url)
+                    .headers(headers.clone())
+                    .json(&body)
+            })
+            .await?;
+
+        let bytes = response.bytes_stream();
+
+        Ok(futures::stream::unfold(
+            (bytes, String::new()),
+            |(mut bytes, mut buffer)| async move {
+                loop {
+                    if let Some(pos) = buffer.find('\n') {
+                        let line = buffer[..pos].trim().to_string();
+                        buffer.drain(..=pos);
+
+                        let Some(payload) = line.strip_prefix("data: ") else {
+                            continue;
+                        };
+                        if payload == "[DONE]" {
+                            return None;
+                        }
+
+                        let content = serde_json::from_str::<StreamChunk>(payload)
+                            .ok()
+                            .and_then(|chunk| chunk.choices.into_iter().next())
+                            .and_then(|choice| choice.delta.content);
+
+                        if let Some(content) = content {
+                            return Some((content, (bytes, buffer)));
+                        }
+                        continue;
+                    }
+
+                    match bytes.next().await {
+                        Some(Ok(chunk)) => buffer.push_str(&String::from_utf8_lossy(&chunk)),
+                        Some(Err(_)) | None => return None,
+                    }
+                }
+            },
+        ))
+    }
+
+    /// Appends the completed turn to a channel's history and trims it back
+    /// down to `self.max_history_messages`.
+    fn commit_history(&self, channel_id: ChannelId, user_message: GPTMessage, reply: GPTMessage) {
+        let mut messages = self.history.get(&channel_id).map_or(Vec::new(), |h| h.clone());
+        messages.push(user_message);
+        messages.push(reply);
+
+        if messages.len() > self.max_history_messages {
+            let overflow = messages.len() - self.max_history_messages;
+            messages.drain(0..overflow);
+        }
+        self.history.insert(channel_id, messages);
+    }
+
+    fn reset_history(&self, channel_id: ChannelId) {
+        self.history.remove(&channel_id);
+    }
+
+    /// The `role: "system"` message to prepend for a channel, based on
+    /// whichever persona it has selected (or [`DEFAULT_PERSONA`] if none).
+    /// Returns `None` when that persona has no prompt configured.
+    fn system_message_for(&self, channel_id: ChannelId) -> Option<GPTMessage> {
+        let persona = self
+            .channel_persona
+            .get(&channel_id)
+            .map(|p| p.clone())
+            .unwrap_or_else(|| DEFAULT_PERSONA.to_string());
+
+        self.personas.get(&persona).map(|prompt| GPTMessage {
+            role: "system".to_string(),
+            content: prompt.clone(),
+        })
+    }
+
+    /// Switches a channel's persona, replying with a confirmation, the list
+    /// of known personas, or an error for an unknown name.
+    fn set_persona(&self, channel_id: ChannelId, name: &str) -> String {
+        if name.is_empty() {
+            let available = self.personas.keys().cloned().collect::<Vec<_>>().join(", ");
+            return if available.is_empty() {
+                "No personas are configured.".to_string()
+            } else {
+                format!("Available personas: {available}")
+            };
+        }
+
+        if self.personas.contains_key(name) {
+            self.channel_persona.insert(channel_id, name.to_string());
+            format!("Persona set to `{name}`.")
+        } else {
+            format!("Unknown persona `{name}`.")
+        }
     }
 }
 
@@ -83,19 +606,167 @@ impl EventHandler for Bot {
             return;
         }
 
+        if msg.content.trim() == RESET_COMMAND {
+            self.reset_history(msg.channel_id);
+            msg.reply(&ctx.http, "Conversation history cleared.")
+                .await
+                .unwrap();
+            return;
+        }
+
+        if let Some(name) = strip_command(msg.content.trim(), PERSONA_COMMAND) {
+            let reply = self.set_persona(msg.channel_id, name.trim());
+            msg.reply(&ctx.http, reply).await.unwrap();
+            return;
+        }
+
+        if let Some(rest) = strip_command(msg.content.trim(), IMAGE_COMMAND) {
+            let (prompt, n, size) = parse_image_args(rest.trim());
+            if prompt.is_empty() {
+                msg.reply(&ctx.http, "Usage: `!image <prompt> [--n N] [--size WxH]`")
+                    .await
+                    .unwrap();
+                return;
+            }
+            let n = n.clamp(1, MAX_IMAGES_PER_REQUEST);
+
+            match self.ask_dalle(&prompt, n, &size).await {
+                Ok(urls) => {
+                    let mut attachments = Vec::new();
+                    for url in urls {
+                        if let Ok(attachment) = CreateAttachment::url(&ctx.http, &url).await {
+                            attachments.push(attachment);
+                        }
+                    }
+                    if attachments.is_empty() {
+                        msg.reply(&ctx.http, ERROR_MESSAGE).await.unwrap();
+                        return;
+                    }
+                    let _ = msg
+                        .channel_id
+                        .send_files(&ctx.http, attachments, CreateMessage::new())
+                        .await;
+                }
+                Err(AskGptError::RateLimited) => {
+                    msg.reply(&ctx.http, RATE_LIMITED_MESSAGE).await.unwrap();
+                }
+                Err(AskGptError::Http(_)) | Err(AskGptError::Request(_)) => {
+                    msg.reply(&ctx.http, ERROR_MESSAGE).await.unwrap();
+                }
+            }
+            return;
+        }
+
+        if let Some(rest) = strip_command(msg.content.trim(), ASK_COMMAND) {
+            let (mut config, prompt) = parse_generation_args(rest.trim(), &self.generation_defaults);
+            config.n = config.n.map(|n| n.clamp(1, MAX_GENERATION_N));
+            if prompt.is_empty() {
+                msg.reply(
+                    &ctx.http,
+                    "Usage: `!ask [--temp T] [--max N] [--top_p P] [--n N] [--model NAME] <prompt>`",
+                )
+                .await
+                .unwrap();
+                return;
+            }
+
+            let provider = self
+                .providers
+                .get(DEFAULT_PROVIDER)
+                .expect("default provider is always configured");
+            let message = GPTMessage {
+                role: "user".to_string(),
+                content: prompt,
+            };
+
+            match self
+                .ask_gpt_with_config(provider, msg.channel_id, &message, &config)
+                .await
+            {
+                Ok(choices) if choices.is_empty() => {
+                    msg.reply(&ctx.http, ERROR_MESSAGE).await.unwrap();
+                }
+                Ok(mut choices) if choices.len() == 1 => {
+                    let reply = choices.remove(0);
+                    for chunk in split_into_chunks(&reply.content, DISCORD_MESSAGE_LIMIT) {
+                        msg.reply(&ctx.http, chunk).await.unwrap();
+                    }
+                    self.commit_history(msg.channel_id, message, reply);
+                }
+                Ok(choices) => {
+                    let formatted = choices
+                        .iter()
+                        .enumerate()
+                        .map(|(i, choice)| format!("**{}.** {}", i + 1, choice.content))
+                        .collect::<Vec<_>>()
+                        .join("\n\n");
+                    for chunk in split_into_chunks(&formatted, DISCORD_MESSAGE_LIMIT) {
+                        msg.reply(&ctx.http, chunk).await.unwrap();
+                    }
+                }
+                Err(AskGptError::RateLimited) => {
+                    msg.reply(&ctx.http, RATE_LIMITED_MESSAGE).await.unwrap();
+                }
+                Err(AskGptError::Http(_)) | Err(AskGptError::Request(_)) => {
+                    msg.reply(&ctx.http, ERROR_MESSAGE).await.unwrap();
+                }
+            }
+            return;
+        }
+
+        let (provider, content) = self.resolve_provider(&msg.content);
         let message = GPTMessage {
             role: "user".to_string(),
-            content: msg.content.clone(),
+            content,
         };
 
-        match self.ask_gpt(message).await {
-            Ok(response) => {
-                msg.reply(&ctx.http, response.content.clone())
-                    .await
-                    .unwrap();
+        let mut placeholder = match msg.reply(&ctx.http, STREAM_PLACEHOLDER).await {
+            Ok(placeholder) => placeholder,
+            Err(_) => return,
+        };
+
+        match self.ask_gpt_stream(provider, msg.channel_id, &message).await {
+            Ok(stream) => {
+                tokio::pin!(stream);
+                let mut reply = String::new();
+                let mut last_edit = Instant::now();
+
+                while let Some(chunk) = stream.next().await {
+                    reply.push_str(&chunk);
+                    if last_edit.elapsed() >= STREAM_EDIT_INTERVAL {
+                        let _ = placeholder
+                            .edit(&ctx.http, EditMessage::new().content(&reply))
+                            .await;
+                        last_edit = Instant::now();
+                    }
+                }
+
+                if reply.is_empty() {
+                    let _ = placeholder
+                        .edit(&ctx.http, EditMessage::new().content(ERROR_MESSAGE))
+                        .await;
+                    return;
+                }
+
+                let _ = placeholder
+                    .edit(&ctx.http, EditMessage::new().content(&reply))
+                    .await;
+
+                let assistant_message = GPTMessage {
+                    role: "assistant".to_string(),
+                    content: reply,
+                };
+                self.commit_history(msg.channel_id, message, assistant_message);
+            }
+            Err(AskGptError::RateLimited) => {
+                let _ = placeholder
+                    .edit(&ctx.http, EditMessage::new().content(RATE_LIMITED_MESSAGE))
+                    .await;
             }
-            Err(_) => {
-                msg.reply(&ctx.http, ERROR_MESSAGE).await.unwrap();
+            Err(AskGptError::Http(_)) | Err(AskGptError::Request(_)) => {
+                let _ = placeholder
+                    .edit(&ctx.http, EditMessage::new().content(ERROR_MESSAGE))
+                    .await;
             }
         }
     }
@@ -123,9 +794,32 @@ async fn main() {
     let openai_token = openai_token.unwrap();
     let http_client = reqwest::Client::new();
 
+    let mut providers = HashMap::new();
+    providers.insert(
+        DEFAULT_PROVIDER.to_string(),
+        Provider {
+            base_url: env::var("OPENAI_API_BASE").unwrap_or_else(|_| ENDPOINT.to_string()),
+            api_key: openai_token.clone(),
+            model: env::var("OPENAI_MODEL").unwrap_or_else(|_| DEFAULT_MODEL.to_string()),
+        },
+    );
+    for alias in ["gpt4", "mistral"] {
+        if let Some(provider) = provider_from_env(alias, &openai_token) {
+            providers.insert(alias.to_string(), provider);
+        }
+    }
+
     let bot = Bot {
-        openai_token,
         http_client,
+        history: DashMap::new(),
+        providers,
+        personas: load_personas(),
+        channel_persona: DashMap::new(),
+        generation_defaults: GenerationConfig::from_env(),
+        max_history_messages: env::var("HISTORY_MAX_MESSAGES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_MAX_HISTORY_MESSAGES),
     };
 
     let intents = GatewayIntents::DIRECT_MESSAGES | GatewayIntents::MESSAGE_CONTENT;
@@ -140,3 +834,78 @@ async fn main() {
         process::exit(1);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strip_command_requires_a_word_boundary() {
+        assert_eq!(strip_command("!persona", "!persona"), Some(""));
+        assert_eq!(strip_command("!persona foo", "!persona"), Some("foo"));
+        assert_eq!(strip_command("!personally", "!persona"), None);
+        assert_eq!(strip_command("!persona-foo", "!persona"), None);
+    }
+
+    #[test]
+    fn parse_image_args_extracts_flags_and_prompt() {
+        let (prompt, n, size) = parse_image_args("a cat --n 3 --size 512x512 in a hat");
+        assert_eq!(prompt, "a cat in a hat");
+        assert_eq!(n, 3);
+        assert_eq!(size, "512x512");
+    }
+
+    #[test]
+    fn parse_image_args_falls_back_to_defaults() {
+        let (prompt, n, size) = parse_image_args("a plain prompt");
+        assert_eq!(prompt, "a plain prompt");
+        assert_eq!(n, 1);
+        assert_eq!(size, DEFAULT_IMAGE_SIZE);
+    }
+
+    #[test]
+    fn parse_generation_args_overrides_defaults() {
+        let defaults = GenerationConfig {
+            model: None,
+            temperature: Some(0.7),
+            max_tokens: None,
+            top_p: None,
+            n: None,
+        };
+        let (config, prompt) =
+            parse_generation_args("--temp 0.2 --max 500 --n 2 what's up", &defaults);
+        assert_eq!(prompt, "what's up");
+        assert_eq!(config.temperature, Some(0.2));
+        assert_eq!(config.max_tokens, Some(500));
+        assert_eq!(config.n, Some(2));
+    }
+
+    #[test]
+    fn is_retryable_status_distinguishes_hard_failures() {
+        assert!(is_retryable_status(reqwest::StatusCode::TOO_MANY_REQUESTS));
+        assert!(is_retryable_status(
+            reqwest::StatusCode::INTERNAL_SERVER_ERROR
+        ));
+        assert!(!is_retryable_status(reqwest::StatusCode::UNAUTHORIZED));
+        assert!(!is_retryable_status(reqwest::StatusCode::BAD_REQUEST));
+    }
+
+    #[test]
+    fn backoff_delay_grows_and_is_capped() {
+        assert!(backoff_delay(0) < backoff_delay(3));
+        assert!(backoff_delay(20) <= MAX_RETRY_DELAY + Duration::from_millis(250));
+    }
+
+    #[test]
+    fn split_into_chunks_respects_the_limit() {
+        let chunks = split_into_chunks(&"a".repeat(2500), 2000);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].len(), 2000);
+        assert_eq!(chunks[1].len(), 500);
+    }
+
+    #[test]
+    fn split_into_chunks_handles_empty_input() {
+        assert_eq!(split_into_chunks("", 2000), vec![String::new()]);
+    }
+}